@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::marker::PhantomData;
 use std::rc::Rc;
 
 use crate::bindings;
@@ -12,20 +13,104 @@ use crate::Display;
 use crate::SurfaceMemoryDescriptor;
 use crate::VaError;
 
+/// A scoped, repeatable mapping of a `VABufferID` via `vaMapBuffer`/`vaUnmapBuffer`.
+///
+/// Separates buffer ownership from the map/unmap lifecycle: it can be held without an active
+/// mapping, queried for validity, and mapped and unmapped repeatedly over its lifetime without
+/// destroying the underlying buffer. [`Image`] is a thin wrapper around this type, forwarding
+/// its `is_valid`/`map`/`unmap` surface to it.
+pub(crate) struct ScopedVABufferMapping {
+    display: Rc<Display>,
+    buffer_id: bindings::VABufferID,
+    size: usize,
+    addr: Option<*mut std::ffi::c_void>,
+}
+
+impl ScopedVABufferMapping {
+    /// Creates a new wrapper around `buffer_id`, without mapping it.
+    pub(crate) fn new(display: &Rc<Display>, buffer_id: bindings::VABufferID, size: usize) -> Self {
+        Self {
+            display: Rc::clone(display),
+            buffer_id,
+            size,
+            addr: None,
+        }
+    }
+
+    /// Returns whether the buffer is currently mapped.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.addr.is_some()
+    }
+
+    /// Maps the buffer via `vaMapBuffer`. A no-op if already mapped.
+    pub(crate) fn map(&mut self) -> Result<(), VaError> {
+        if self.addr.is_some() {
+            return Ok(());
+        }
+
+        let mut addr = std::ptr::null_mut();
+
+        // Safe since `self.buffer_id` represents a valid `VABufferID`.
+        va_check(unsafe {
+            bindings::vaMapBuffer(self.display.handle(), self.buffer_id, &mut addr)
+        })?;
+
+        self.addr = Some(addr);
+        Ok(())
+    }
+
+    /// Unmaps the buffer via `vaUnmapBuffer`. A no-op if not currently mapped.
+    pub(crate) fn unmap(&mut self) -> Result<(), VaError> {
+        if self.addr.take().is_none() {
+            return Ok(());
+        }
+
+        // Safe since `self.buffer_id` represents a valid, currently mapped `VABufferID`.
+        va_check(unsafe { bindings::vaUnmapBuffer(self.display.handle(), self.buffer_id) })
+    }
+
+    /// Returns the mapped data, or `None` if the buffer is not currently mapped.
+    pub(crate) fn data(&self) -> Option<&[u8]> {
+        // Safe since `addr` points to data mapped onto our address space for `self.size` bytes
+        // for as long as it is held in `self.addr`.
+        self.addr
+            .map(|addr| unsafe { std::slice::from_raw_parts(addr as *const u8, self.size) })
+    }
+
+    /// Returns the mapped data as a mutable slice, or `None` if the buffer is not currently
+    /// mapped.
+    pub(crate) fn data_mut(&mut self) -> Option<&mut [u8]> {
+        // Safe since `addr` points to data mapped onto our address space for `self.size` bytes
+        // for as long as it is held in `self.addr`.
+        self.addr
+            .map(|addr| unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, self.size) })
+    }
+}
+
+impl Drop for ScopedVABufferMapping {
+    fn drop(&mut self) {
+        // Nothing we can do if this fails, as we are dropping the mapping anyway.
+        let _ = self.unmap();
+    }
+}
+
 /// Wrapper around `VAImage` that is tied to the lifetime of a given `Picture`.
 ///
 /// An image is used to either get the surface data to client memory, or to copy image data in
 /// client memory to a surface.
 pub struct Image<'a> {
-    /// The display from which the image was created, so we can unmap it upon destruction.
-    display: Rc<Display>,
+    /// The surface the image was created from, so we can upload to it via `vaPutImage`.
+    surface_id: bindings::VASurfaceID,
     /// The `VAImage` returned by libva.
     image: bindings::VAImage,
-    /// The mapped surface data.
-    data: &'a [u8],
+    /// The scoped mapping of `image.buf`. Mapped on construction, but may be unmapped and
+    /// remapped later via [`Image::unmap`] and [`Image::map`].
+    mapping: ScopedVABufferMapping,
     /// Whether the image was derived using the `vaDeriveImage` API or created using the
     /// `vaCreateImage` API.
     derived: bool,
+    /// Ties this `Image` to the lifetime of the `Picture` it was created from.
+    _phantom: PhantomData<&'a ()>,
 }
 
 impl<'a> Image<'a> {
@@ -37,26 +122,17 @@ impl<'a> Image<'a> {
         image: bindings::VAImage,
         derived: bool,
     ) -> Result<Self, VaError> {
-        let mut addr = std::ptr::null_mut();
+        let mut mapping =
+            ScopedVABufferMapping::new(picture.display(), image.buf, image.data_size as usize);
 
-        // Safe since `picture.inner.context` represents a valid `VAContext` and `image` has been
-        // successfully created at this point.
-        match va_check(unsafe {
-            bindings::vaMapBuffer(picture.display().handle(), image.buf, &mut addr)
-        }) {
-            Ok(_) => {
-                // Safe since `addr` points to data mapped onto our address space since we called
-                // `vaMapBuffer` above, which also guarantees that the data is valid for
-                // `image.data_size`.
-                let data =
-                    unsafe { std::slice::from_raw_parts_mut(addr as _, image.data_size as usize) };
-                Ok(Image {
-                    display: Rc::clone(picture.display()),
-                    image,
-                    data,
-                    derived,
-                })
-            }
+        match mapping.map() {
+            Ok(()) => Ok(Image {
+                surface_id: picture.surface_id(),
+                image,
+                mapping,
+                derived,
+                _phantom: PhantomData,
+            }),
             Err(e) => {
                 // Safe because `picture.inner.context` represents a valid `VAContext` and `image`
                 // represents a valid `VAImage`.
@@ -79,22 +155,169 @@ impl<'a> Image<'a> {
     pub fn is_derived(&self) -> bool {
         self.derived
     }
+
+    /// Returns whether this image's buffer is currently mapped, i.e. whether [`Image::as_ref`],
+    /// [`Image::as_mut_slice`] and [`Image::plane`] are usable.
+    pub fn is_valid(&self) -> bool {
+        self.mapping.is_valid()
+    }
+
+    /// Maps this image's buffer via `vaMapBuffer`, so its data can be read or written again after
+    /// a call to [`Image::unmap`]. A no-op if already mapped.
+    ///
+    /// This lets a client hold on to an `Image` across several frames and lazily map it only when
+    /// needed, without destroying and recreating the underlying `VAImage`.
+    pub fn map(&mut self) -> Result<(), VaError> {
+        self.mapping.map()
+    }
+
+    /// Unmaps this image's buffer via `vaUnmapBuffer`, without destroying the underlying
+    /// `VAImage`. A no-op if not currently mapped.
+    ///
+    /// The image can be mapped again later with [`Image::map`].
+    pub fn unmap(&mut self) -> Result<(), VaError> {
+        self.mapping.unmap()
+    }
+
+    /// Returns a view into the `index`th plane of this image, or `None` if `index` is out of
+    /// range, the image is currently unmapped, or the driver-reported plane geometry does not fit
+    /// within the mapped buffer.
+    ///
+    /// This spares callers decoding into multi-planar formats like NV12, I420 or P010 from having
+    /// to re-derive the plane layout from `self.image()` themselves.
+    pub fn plane(&self, index: usize) -> Option<PlaneView<'_>> {
+        // `pitches`/`offsets` are fixed-size arrays: guard against a driver reporting a bogus
+        // `num_planes` beyond their length, not just an out-of-range `index`.
+        if index >= self.image.num_planes as usize || index >= self.image.pitches.len() {
+            return None;
+        }
+
+        let pitch = self.image.pitches[index];
+        let offset = self.image.offsets[index] as usize;
+        let rows = plane_rows(self.image.format.fourcc, index, self.image.height);
+        let len = pitch as usize * rows as usize;
+        let data = self.mapping.data()?;
+        let end = offset.checked_add(len)?;
+
+        if end > data.len() {
+            // The driver-reported offset/pitch/height geometry doesn't fit within the mapped
+            // buffer: bail out rather than risk a panic on an unanticipated fourcc or padding
+            // quirk.
+            return None;
+        }
+
+        Some(PlaneView {
+            data: &data[offset..end],
+            pitch,
+            rows,
+        })
+    }
+
+    /// Returns an iterator over the planes of this image, in plane order.
+    ///
+    /// Planes for which [`Image::plane`] returns `None` are skipped, so nothing is yielded while
+    /// the image is unmapped.
+    pub fn planes(&self) -> impl Iterator<Item = PlaneView<'_>> {
+        (0..self.image.num_planes as usize).filter_map(move |index| self.plane(index))
+    }
+
+    /// Returns the mapped surface data as a mutable slice, so client code can write pixel data
+    /// into it before calling [`Image::put`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the image is currently unmapped (see [`Image::is_valid`] and [`Image::map`]).
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.mapping
+            .data_mut()
+            .expect("image is not currently mapped: call `Image::map` first")
+    }
+
+    /// Uploads the client-side pixel data held by this image onto the surface it was created
+    /// from, via `vaPutImage`.
+    ///
+    /// Derived images already alias the surface's own storage, so any write to the mapped data is
+    /// already visible to the surface and issuing `vaPutImage` would be redundant: this is a
+    /// no-op in that case.
+    pub fn put(mut self) -> Result<(), VaError> {
+        if self.derived {
+            return Ok(());
+        }
+
+        let width = self.image.width as i32;
+        let height = self.image.height as i32;
+
+        // `vaPutImage` must not race a live CPU mapping of the same buffer, so unmap it first.
+        self.mapping.unmap()?;
+
+        // Safe since `self.mapping` and `self.surface_id` are valid, and `self.image` represents
+        // a valid `VAImage`.
+        va_check(unsafe {
+            bindings::vaPutImage(
+                self.mapping.display.handle(),
+                self.surface_id,
+                self.image.image_id,
+                0,
+                0,
+                width,
+                height,
+                0,
+                0,
+                width,
+                height,
+            )
+        })
+    }
+}
+
+/// A view into a single plane of a (possibly multi-planar) [`Image`].
+pub struct PlaneView<'a> {
+    /// The raw plane data, starting at the plane's offset and spanning `pitch * rows` bytes.
+    pub data: &'a [u8],
+    /// The distance in bytes between the start of two consecutive rows.
+    pub pitch: u32,
+    /// The number of rows in this plane, accounting for chroma subsampling.
+    pub rows: u32,
+}
+
+/// Returns the number of rows of plane `index` for an image of the given `fourcc` and full
+/// `height`, accounting for the chroma subsampling of common multi-planar formats.
+fn plane_rows(fourcc: u32, index: usize, height: u32) -> u32 {
+    match fourcc {
+        // NV12 and P010 are 4:2:0 with a single, half-height chroma plane.
+        bindings::VA_FOURCC_NV12 | bindings::VA_FOURCC_P010 if index == 1 => (height + 1) / 2,
+        // I420 and YV12 are 4:2:0 with two half-height chroma planes.
+        bindings::VA_FOURCC_I420 | bindings::VA_FOURCC_YV12 if index > 0 => (height + 1) / 2,
+        _ => height,
+    }
 }
 
 impl<'a> AsRef<[u8]> for Image<'a> {
+    /// # Panics
+    ///
+    /// Panics if the image is currently unmapped (see [`Image::is_valid`] and [`Image::map`]).
     fn as_ref(&self) -> &[u8] {
-        self.data
+        self.mapping
+            .data()
+            .expect("image is not currently mapped: call `Image::map` first")
+    }
+}
+
+impl<'a> AsMut<[u8]> for Image<'a> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
     }
 }
 
 impl<'a> Drop for Image<'a> {
     fn drop(&mut self) {
+        // Unmap the buffer, if it is still mapped, before the `VAImage` owning it is destroyed.
+        // Nothing we can do if this fails, as we are dropping the image anyway.
+        let _ = self.mapping.unmap();
+
         unsafe {
-            // Safe since the buffer is mapped in `Image::new`, so `self.image.buf` points to a
-            // valid `VABufferID`.
-            bindings::vaUnmapBuffer(self.display.handle(), self.image.buf);
             // Safe since `self.image` represents a valid `VAImage`.
-            bindings::vaDestroyImage(self.display.handle(), self.image.image_id);
+            bindings::vaDestroyImage(self.mapping.display.handle(), self.image.image_id);
         }
     }
 }